@@ -1,9 +1,12 @@
 use std::collections::HashMap;
 use std::io::Result as Res;
 use std::net::{SocketAddr, ToSocketAddrs};
+use std::os::fd::{AsRawFd, RawFd};
+use std::time::{Duration, Instant};
 
 use endio::{LERead, LEWrite};
 
+use crate::fault_injection::FaultInjection;
 use crate::raknet::Connection as RakConn;
 use crate::raknet::MAX_PACKET_SIZE;
 use crate::string::WriteStr;
@@ -22,6 +25,8 @@ pub enum MessageType {
 	NoFreeIncomingConnections = 18,
 	/// The client has disconnected voluntarily.
 	DisconnectNotification = 19,
+	/// The connection has timed out or otherwise been lost without a voluntary disconnect.
+	ConnectionLost = 20,
 }
 
 /// Reliablity types supported by RakNet. `ReliableSequenced` is also one of them but is never used in practice, so it's omitted from this program entirely.
@@ -57,6 +62,12 @@ pub struct Bridge {
 	raknet_socket: UdpSocket,
 	raknet_buffer: [u8; MAX_PACKET_SIZE * 5],
 	config: AppConfig,
+	/// Timestamp of the last successfully received packet, on either end. Used to reap idle bridges.
+	last_activity: Instant,
+	/// Whether a keepalive ping has already been sent for the current idle period, so it isn't repeated every tick.
+	keepalive_sent: bool,
+	/// Artificial latency/jitter/loss simulation, if configured.
+	fault_injection: Option<FaultInjection>,
 }
 
 impl Bridge {
@@ -65,6 +76,7 @@ impl Bridge {
 			raknet_to_server_socket.try_clone().unwrap(),
 			raknet_to_server_socket.peer_addr().unwrap(),
 		);
+		let fault_injection = config.fault_injection.clone().map(FaultInjection::new);
 
 		Bridge {
 			conn_to_client: tcp_conn,
@@ -72,11 +84,120 @@ impl Bridge {
 			raknet_socket: raknet_to_server_socket,
 			raknet_buffer: [0; MAX_PACKET_SIZE * 5],
 			config,
+			last_activity: Instant::now(),
+			keepalive_sent: false,
+			fault_injection,
 		}
 	}
 
+	/// Sends packets destined for the TcpUdp end, routing them through the fault-injection queue first if it's enabled.
+	fn route_to_client(&mut self, packets: Vec<Packet>) -> Res<()> {
+		match &mut self.fault_injection {
+			None => self.conn_to_client.send_packets(packets),
+			Some(fault_injection) => {
+				for packet in packets {
+					fault_injection.enqueue_to_client(packet);
+				}
+				Ok(())
+			}
+		}
+	}
+
+	/// Sends packets destined for the RakNet end, routing them through the fault-injection queue first if it's enabled.
+	fn route_to_server(&mut self, packets: Vec<Packet>) -> Res<()> {
+		match &mut self.fault_injection {
+			None => self.conn_to_server.send(packets),
+			Some(fault_injection) => {
+				for packet in packets {
+					fault_injection.enqueue_to_server(packet);
+				}
+				Ok(())
+			}
+		}
+	}
+
+	/// Forwards any packets in the fault-injection queues whose simulated arrival time has passed. A no-op if fault injection isn't configured.
+	pub fn drain_fault_injection(&mut self) -> Res<()> {
+		let Some(fault_injection) = &mut self.fault_injection else {
+			return Ok(());
+		};
+		let (to_client, to_server) = fault_injection.drain();
+		if !to_client.is_empty() {
+			self.conn_to_client.send_packets(to_client)?;
+		}
+		if !to_server.is_empty() {
+			self.conn_to_server.send(to_server)?;
+		}
+		Ok(())
+	}
+
+	/// Records that a packet was just received, resetting the idle and keepalive timers.
+	fn touch(&mut self) {
+		self.last_activity = Instant::now();
+		self.keepalive_sent = false;
+	}
+
+	/// True if this bridge hasn't seen any activity within the configured idle timeout. Always false if idle reaping is disabled (timeout of 0).
+	pub fn is_idle(&self) -> bool {
+		self.config.idle_timeout_secs != 0
+			&& self.last_activity.elapsed() >= Duration::from_secs(self.config.idle_timeout_secs)
+	}
+
+	/**
+		Sends a lightweight unreliable ping to the TcpUdp end once this bridge has been quiet for half the idle timeout, so transient idleness (and not just a dead peer) doesn't immediately look like one.
+	*/
+	pub fn maybe_send_keepalive(&mut self) -> Res<()> {
+		if self.config.idle_timeout_secs == 0 || self.keepalive_sent {
+			return Ok(());
+		}
+		let half_timeout = Duration::from_secs(self.config.idle_timeout_secs) / 2;
+		if self.last_activity.elapsed() >= half_timeout {
+			self.route_to_client(vec![Packet {
+				reliability: Reliability::Unreliable,
+				data: Box::new([]),
+			}])?;
+			self.keepalive_sent = true;
+		}
+		Ok(())
+	}
+
+	/// Raw file descriptor of the socket used to talk to the RakNet end, for registering with a readiness poller.
+	pub fn raknet_raw_fd(&self) -> RawFd {
+		self.raknet_socket.as_raw_fd()
+	}
+
+	/// Raw file descriptor of the reliable transport used to talk to the TcpUdp end, for registering with a readiness poller.
+	pub fn client_raw_fd(&self) -> RawFd {
+		self.conn_to_client.raw_fd()
+	}
+
+	/**
+		How long until this bridge next needs attention purely due to the passage of time (an idle timeout, a keepalive, or a queued fault-injection packet becoming due), as opposed to socket readiness.
+
+		Used to bound how long the main loop's readiness poll is allowed to block.
+	*/
+	pub fn next_timer(&self) -> Option<Duration> {
+		let mut deadlines = Vec::new();
+		if self.config.idle_timeout_secs != 0 {
+			let timeout = Duration::from_secs(self.config.idle_timeout_secs);
+			let elapsed = self.last_activity.elapsed();
+			deadlines.push(timeout.saturating_sub(elapsed));
+			if !self.keepalive_sent {
+				deadlines.push((timeout / 2).saturating_sub(elapsed));
+			}
+		}
+		if let Some(fault_injection) = &self.fault_injection {
+			if let Some(next) = fault_injection.next_release() {
+				deadlines.push(next);
+			}
+		}
+		deadlines.into_iter().min()
+	}
+
 	pub fn client_receive(&mut self) -> Res<Box<[u8]>> {
-		self.conn_to_client.receive_raw()
+		let data = self.conn_to_client.receive_raw()?;
+		self.touch();
+		Ok(data)
 	}
 
 	pub fn server_receive(
@@ -96,27 +217,62 @@ impl Bridge {
 					return Err(err);
 				}
 			};
+			self.touch();
 
 			let mut packets = self
 				.conn_to_server
 				.handle_datagram(&self.raknet_buffer[..length])?;
 
+			if packets.iter().any(Self::is_disconnect) {
+				return Err(std::io::Error::new(
+					std::io::ErrorKind::ConnectionAborted,
+					"received RakNet disconnect/connection-lost notification",
+				));
+			}
+
 			let mut cmds = self.scan_packets(&mut packets, addrs)?;
 			return_vec.append(&mut cmds);
-			self.conn_to_client.send_packets(packets)?;
+			self.route_to_client(packets)?;
 		}
 	}
 
+	/// True if `packet` is a RakNet `DisconnectNotification` or `ConnectionLost` control message.
+	fn is_disconnect(packet: &Packet) -> bool {
+		matches!(
+			packet.data.first(),
+			Some(&x) if x == MessageType::DisconnectNotification as u8 || x == MessageType::ConnectionLost as u8
+		)
+	}
+
 	/// Receives any incoming packets on the RakNet end and sends them on the TcpUdp end.
 	pub fn forward_to_server(&mut self, data: &[u8]) -> Res<()> {
 		let packets = vec![Packet {
 			reliability: Reliability::Reliable,
 			data: data.to_vec().into_boxed_slice(),
 		}];
+		self.route_to_server(packets)?;
+		Ok(())
+	}
+
+	/// Tells the RakNet end that the TcpUdp end has closed, so the remote server can clean up instead of waiting for its own timeout. Sent directly, bypassing fault injection, since it's teardown signaling rather than simulated application traffic.
+	pub fn notify_disconnect(&mut self) -> Res<()> {
+		let packets = vec![Packet {
+			reliability: Reliability::Reliable,
+			data: Box::new([MessageType::DisconnectNotification as u8]),
+		}];
 		self.conn_to_server.send(packets)?;
 		Ok(())
 	}
 
+	/// Parses a raw unreliable datagram received on the TcpUdp end and, unless it's a stale `UnreliableSequenced` packet, forwards it to the RakNet end with its reliability preserved.
+	pub fn forward_unreliable_to_server(&mut self, datagram: &[u8]) -> Res<()> {
+		self.touch();
+		if let Some(packet) = self.conn_to_client.receive_unreliable(datagram)? {
+			self.route_to_server(vec![packet])?;
+		}
+		Ok(())
+	}
+
 	/**
 		Scans packets for certain messages and replaces data if necessary.
 
@@ -0,0 +1,109 @@
+/*!
+	An optional fault-injection layer for simulating adverse network conditions during testing.
+
+	When enabled via `AppConfig`, each packet a `Bridge` would otherwise send immediately is instead subjected to a per-direction drop probability and, if it survives, queued with a release time offset by a base latency plus random jitter. The queue is drained once per `Shim::step`, so packets are only ever forwarded once their release time has passed. This is gated off by default (`AppConfig::fault_injection` is `None`) so production relays see no overhead or behavior change.
+*/
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+use serde::Deserialize;
+
+use crate::bridge::Packet;
+
+/// Configuration for the fault-injection layer. Applied identically to both directions of a `Bridge`, each of which keeps its own independent queue.
+#[derive(Deserialize, Debug, Clone)]
+pub struct FaultInjectionConfig {
+	/// Chance, in percent (0-100), that a packet is dropped instead of queued.
+	loss_rate: u8,
+	/// Minimum delay applied to a packet that isn't dropped.
+	base_latency_ms: u64,
+	/// Additional random delay, uniformly distributed between 0 and this value, added on top of `base_latency_ms`.
+	jitter_ms: u64,
+}
+
+/// A queued packet awaiting its simulated arrival time.
+struct Delayed {
+	release_at: Instant,
+	packet: Packet,
+}
+
+/// Per-`Bridge` fault-injection state: the config plus one delay queue per direction.
+pub struct FaultInjection {
+	config: FaultInjectionConfig,
+	to_client: VecDeque<Delayed>,
+	to_server: VecDeque<Delayed>,
+}
+
+impl FaultInjection {
+	pub fn new(config: FaultInjectionConfig) -> Self {
+		FaultInjection {
+			config,
+			to_client: VecDeque::new(),
+			to_server: VecDeque::new(),
+		}
+	}
+
+	fn delay(&self) -> Duration {
+		let jitter = if self.config.jitter_ms == 0 {
+			0
+		} else {
+			rand::thread_rng().gen_range(0..=self.config.jitter_ms)
+		};
+		Duration::from_millis(self.config.base_latency_ms + jitter)
+	}
+
+	fn should_drop(&self) -> bool {
+		rand::thread_rng().gen_range(0..100) < self.config.loss_rate
+	}
+
+	/// Queues a packet bound for the TcpUdp end, unless it's randomly dropped.
+	pub fn enqueue_to_client(&mut self, packet: Packet) {
+		if self.should_drop() {
+			return;
+		}
+		self.to_client.push_back(Delayed {
+			release_at: Instant::now() + self.delay(),
+			packet,
+		});
+	}
+
+	/// Queues a packet bound for the RakNet end, unless it's randomly dropped.
+	pub fn enqueue_to_server(&mut self, packet: Packet) {
+		if self.should_drop() {
+			return;
+		}
+		self.to_server.push_back(Delayed {
+			release_at: Instant::now() + self.delay(),
+			packet,
+		});
+	}
+
+	/// Pops every packet in `queue` whose release time has passed, in order.
+	fn drain_ready(queue: &mut VecDeque<Delayed>, now: Instant) -> Vec<Packet> {
+		let mut ready = Vec::new();
+		while matches!(queue.front(), Some(delayed) if delayed.release_at <= now) {
+			ready.push(queue.pop_front().unwrap().packet);
+		}
+		ready
+	}
+
+	/// How long until the earliest queued packet, in either direction, becomes due. `None` if both queues are empty.
+	pub fn next_release(&self) -> Option<Duration> {
+		let earliest = [self.to_client.front(), self.to_server.front()]
+			.into_iter()
+			.flatten()
+			.map(|delayed| delayed.release_at)
+			.min()?;
+		Some(earliest.saturating_duration_since(Instant::now()))
+	}
+
+	/// Returns the packets in both directions that are due to be sent now, removing them from the queues.
+	pub fn drain(&mut self) -> (Vec<Packet>, Vec<Packet>) {
+		let now = Instant::now();
+		(
+			Self::drain_ready(&mut self.to_client, now),
+			Self::drain_ready(&mut self.to_server, now),
+		)
+	}
+}
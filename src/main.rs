@@ -17,24 +17,50 @@
 
 	More information about the new protocol can be found in the documentation for the TcpUdp connection implementation, and info about the translation and interception process can be found in the `Bridge` documentation.
 */
+use mio::unix::SourceFd;
+use mio::{Events, Interest, Poll, Token};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
 use serde::Deserialize;
-use std::collections::HashMap;
+use socket2::{Domain, SockRef, Socket, Type};
+use std::collections::{HashMap, HashSet};
 use std::fs;
+use std::fs::File;
 use std::io;
+use std::io::BufReader;
 use std::io::Result as Res;
 use std::net::TcpListener;
+use std::net::TcpStream;
 use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
-use std::thread;
+use std::os::fd::{AsRawFd, RawFd};
+use std::sync::Arc;
 use std::time::Duration;
 
 mod bridge;
+mod fault_injection;
 mod raknet;
 mod string;
 mod tcpudp;
-use tcpudp::Connection;
+use fault_injection::FaultInjectionConfig;
+use tcpudp::{Connection, ReliableTransport};
 
 use crate::bridge::{Bridge, ShimCommand};
-const SLEEP_TIME: Duration = Duration::from_millis(1000 / 30);
+/// Large enough to hold any datagram the TcpUdp protocol's unreliable side will ever produce, since it never splits packets and keeps them under the MTU.
+const MAX_UDP_DATAGRAM_SIZE: usize = 1500;
+/// Upper bound on how long `poll` is allowed to block, so shims/bridges created without any timer pending (no idle timeout, no fault injection) still get revisited periodically.
+const MAX_POLL_TIMEOUT: Duration = Duration::from_secs(1);
+/// Read and write timeout applied to each socket operation of the TLS handshake on a newly accepted connection, so a client that completes the TCP handshake and then goes silent can't stall this single-threaded event loop indefinitely. It bounds each individual read or write, not the handshake as a whole.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Upper bound on how many pending connections `accept_clients` will handshake in a single call, so a full backlog of slow or silent clients is handshaken at most `MAX_ACCEPTS_PER_TICK` at a time rather than all in one call. The listener keeps reporting readable as long as the backlog is non-empty, so anything left over is picked up on a later tick.
+const MAX_ACCEPTS_PER_TICK: usize = 4;
+
+/// What a `mio::Token` registered by a `Shim` refers to, so a readiness event can be routed to the right work instead of rescanning everything.
+#[derive(Clone, Copy)]
+enum PollTarget {
+	/// The listener (new clients) or the shared unreliable socket (datagrams for any of this shim's bridges).
+	Shim,
+	/// One specific bridge's client or RakNet socket.
+	Bridge(SocketAddr),
+}
 
 /// A RakNet server translating and relaying incoming connections to a TcpUdp server.
 pub struct Shim {
@@ -42,9 +68,23 @@ pub struct Shim {
 	connect_addr: SocketAddr,
 	/// The RakNet socket. As UDP is a connectionless protocol, there is only one socket no matter how many clients connect to the server.
 	tcp_listener: TcpListener,
+	/// The socket used for the unreliable side of the TcpUdp protocol. Like `tcp_listener`, there is only one socket shared by all connections accepted by this Shim; individual connections are told apart by peer address.
+	udp_socket: UdpSocket,
 	/// The map from an incoming RakNet address to the bridge responsible for handling the specific connection.
 	bridges: HashMap<SocketAddr, Bridge>,
 	config: AppConfig,
+	/// Built once from `config.tls`, if present, so every accepted connection doesn't have to re-parse the certificate and key.
+	tls_config: Option<Arc<rustls::ServerConfig>>,
+	/// Whether `tcp_listener` and `udp_socket` have already been registered with the main loop's poller.
+	registered: bool,
+	/// Bridges whose sockets have already been registered with the main loop's poller, so new ones are caught without re-registering existing ones.
+	registered_bridges: HashSet<SocketAddr>,
+	/// What each token this Shim has registered with the poller refers to, so a readiness event can be routed back to the right bridge (or to the shim-level sockets) without rescanning everything.
+	token_targets: HashMap<Token, PollTarget>,
+	/// The two tokens registered for each bridge's sockets, so they can be deregistered and removed from `token_targets` when the bridge is torn down, instead of accumulating there for the life of the process.
+	bridge_tokens: HashMap<SocketAddr, (Token, Token)>,
+	/// A handle to the main loop's poller, cloned the first time this Shim registers its sockets, so a bridge can later be deregistered without threading the registry through every call site that might remove one.
+	registry: Option<mio::Registry>,
 }
 
 impl Shim {
@@ -54,37 +94,179 @@ impl Shim {
 			.to_socket_addrs()?
 			.next()
 			.unwrap();
-		let tcp_server = TcpListener::bind(real_listen_addr.to_string().as_str())?;
+		let tcp_server = bind_tcp_listener(real_listen_addr, config.tcp.backlog)?;
 		tcp_server.set_nonblocking(true)?;
+		let udp_server = UdpSocket::bind(real_listen_addr.to_string().as_str())?;
+		udp_server.set_nonblocking(true)?;
+
+		let tls_config = config.tls.as_ref().map(load_tls_config).transpose()?;
 
 		println!("Starting new shim. Listening on {listen_addr}, connecting to RakNet at {connect_addr}.");
 
 		Ok(Shim {
 			connect_addr,
 			tcp_listener: tcp_server,
+			udp_socket: udp_server,
 			bridges: HashMap::new(),
 			config,
+			tls_config,
+			registered: false,
+			registered_bridges: HashSet::new(),
+			token_targets: HashMap::new(),
+			bridge_tokens: HashMap::new(),
+			registry: None,
 		})
 	}
 
+	/**
+		Registers this Shim's own sockets, and any of its bridges' sockets, with the main loop's poller, if they haven't been already.
+
+		Called every tick rather than just once at creation, since bridges are created dynamically as clients connect.
+	*/
+	fn register_sockets(&mut self, registry: &mio::Registry, next_token: &mut usize) -> Res<()> {
+		if !self.registered {
+			register_fd(
+				registry,
+				self.tcp_listener.as_raw_fd(),
+				next_token,
+				&mut self.token_targets,
+				PollTarget::Shim,
+			)?;
+			register_fd(
+				registry,
+				self.udp_socket.as_raw_fd(),
+				next_token,
+				&mut self.token_targets,
+				PollTarget::Shim,
+			)?;
+			self.registered = true;
+			// Cloned so a bridge can be deregistered later (see `deregister_bridge`) without this
+			// method's `&mio::Registry` parameter having to be threaded through every removal site.
+			self.registry = Some(registry.try_clone()?);
+		}
+		for (addr, bridge) in &self.bridges {
+			if self.registered_bridges.insert(*addr) {
+				let raknet_token = register_fd(
+					registry,
+					bridge.raknet_raw_fd(),
+					next_token,
+					&mut self.token_targets,
+					PollTarget::Bridge(*addr),
+				)?;
+				let client_token = register_fd(
+					registry,
+					bridge.client_raw_fd(),
+					next_token,
+					&mut self.token_targets,
+					PollTarget::Bridge(*addr),
+				)?;
+				self.bridge_tokens.insert(*addr, (raknet_token, client_token));
+			}
+		}
+		Ok(())
+	}
+
+	/// Deregisters a torn-down bridge's sockets from the poller and forgets their tokens, so `token_targets`/`bridge_tokens` don't grow without bound as connections churn over the life of the process. `raknet_fd`/`client_fd` must be taken from the bridge before it's dropped, since closing the sockets is what the bridge's own `Drop` does.
+	fn deregister_bridge(&mut self, addr: &SocketAddr, raknet_fd: RawFd, client_fd: RawFd) {
+		self.registered_bridges.remove(addr);
+		if let Some((raknet_token, client_token)) = self.bridge_tokens.remove(addr) {
+			self.token_targets.remove(&raknet_token);
+			self.token_targets.remove(&client_token);
+		}
+		if let Some(registry) = &self.registry {
+			let _ = registry.deregister(&mut SourceFd(&raknet_fd));
+			let _ = registry.deregister(&mut SourceFd(&client_fd));
+		}
+	}
+
+	/// Splits a batch of readiness events into "the shim-level sockets fired" and "these specific bridges fired", based on what was recorded at registration time.
+	fn ready_targets(&self, events: &Events) -> (bool, HashSet<SocketAddr>) {
+		let mut ready_shim = false;
+		let mut ready_bridges = HashSet::new();
+		for event in events.iter() {
+			match self.token_targets.get(&event.token()) {
+				Some(PollTarget::Shim) => ready_shim = true,
+				Some(PollTarget::Bridge(addr)) => {
+					ready_bridges.insert(*addr);
+				}
+				None => {}
+			}
+		}
+		(ready_shim, ready_bridges)
+	}
+
+	/// How long until this Shim's bridges next need attention purely due to the passage of time, across all of them. `None` if none have a pending timer.
+	fn next_timer(&self) -> Option<Duration> {
+		self.bridges.values().filter_map(Bridge::next_timer).min()
+	}
+
 	/// Returns the local address of the RakNet socket. This may not be the same as the `listen_address` passed to `new` if the passed address had 0 as port.
 	pub fn local_addr(&self) -> Res<SocketAddr> {
 		self.tcp_listener.local_addr()
 	}
 
 	/**
-		Checks all sockets for incoming packets and handles them if there are any.
+		Checks for incoming packets and handles them if there are any.
 
-		The RakNet socket is checked by the `raknet_step` method, while the TCP/UDP sockets are checked by the bridge's `tcpudp_receive` method.
+		`ready_shim` and `ready_bridges` come from `ready_targets` and say which sockets actually signaled readiness this wakeup, so only those are read instead of rescanning every bridge of every shim on every tick. Timer-driven bookkeeping (fault-injection release, idle reaping/keepalives) runs regardless of readiness, since it isn't triggered by socket events.
 	*/
 	fn step(
 		&mut self,
 		cmds: &mut Vec<ShimCommand>,
 		addrs: &HashMap<SocketAddr, SocketAddr>,
+		ready_shim: bool,
+		ready_bridges: &HashSet<SocketAddr>,
 	) -> Res<()> {
-		self.client_receive()?;
-		self.bridges
-			.retain(|_addr, bridge| match bridge.server_receive(addrs) {
+		for bridge in self.bridges.values_mut() {
+			bridge.drain_fault_injection().unwrap_or_else(|err| {
+				println!("Error in `step`: {err:?}");
+			});
+		}
+		if ready_shim {
+			self.accept_clients();
+			self.udp_receive()?;
+		}
+		self.reap_idle_bridges();
+		let mut closed = Vec::new();
+		self.bridges.retain(|addr, bridge| {
+			if !ready_bridges.contains(addr) {
+				return true;
+			}
+			match bridge.client_receive() {
+				Ok(msg) => {
+					bridge.forward_to_server(&msg).unwrap_or_else(|err| {
+						println!("Error in `step`: {err:?}");
+					});
+					true
+				}
+				Err(err) => {
+					if err.kind() == io::ErrorKind::ConnectionReset {
+						closed.push((*addr, bridge.raknet_raw_fd(), bridge.client_raw_fd()));
+						return false;
+					}
+					if err.kind() == io::ErrorKind::ConnectionAborted {
+						bridge.notify_disconnect().unwrap_or_else(|err| {
+							println!("Error in `step`: {err:?}");
+						});
+						closed.push((*addr, bridge.raknet_raw_fd(), bridge.client_raw_fd()));
+						return false;
+					}
+					if err.kind() != io::ErrorKind::WouldBlock {
+						dbg!(&err);
+					}
+					true
+				}
+			}
+		});
+		for (addr, raknet_fd, client_fd) in closed {
+			self.deregister_bridge(&addr, raknet_fd, client_fd);
+		}
+		let mut closed = Vec::new();
+		self.bridges.retain(|addr, bridge| {
+			if !ready_bridges.contains(addr) {
+				return true;
+			}
+			match bridge.server_receive(addrs) {
 				Ok(cmd) => {
 					cmds.extend(cmd);
 					true
@@ -95,40 +277,111 @@ impl Shim {
 					} else if err.kind() != io::ErrorKind::ConnectionAborted {
 						println!("Error in `step`: {err:?}");
 					}
+					closed.push((*addr, bridge.raknet_raw_fd(), bridge.client_raw_fd()));
 					false
 				}
-			});
+			}
+		});
+		for (addr, raknet_fd, client_fd) in closed {
+			self.deregister_bridge(&addr, raknet_fd, client_fd);
+		}
 		Ok(())
 	}
 
-	fn client_receive(&mut self) -> Res<()> {
-		while let Ok((stream, addr)) = self.tcp_listener.accept() {
-			let conn = Connection::from(stream)?;
-
-			let new_bridge = self.create_bridge(conn)?;
-			self.bridges.insert(addr, new_bridge);
-		}
+	/**
+		Accepts up to `MAX_ACCEPTS_PER_TICK` clients waiting on the listener.
 
-		self.bridges
-			.retain(|_addr, bridge| match bridge.client_receive() {
-				Ok(msg) => {
-					bridge.forward_to_server(&msg).unwrap_or_else(|err| {
-						println!("Error in `client_receive`: {err:?}");
-					});
-					true
+		Each one is handled in isolation: a client that sends garbage instead of a valid TLS
+		ClientHello, disconnects mid-handshake, or trips an invalid socket option is logged and
+		dropped without affecting any other client or bridge. The accept count is capped so a
+		backlog full of clients that stall out their TLS handshake can't serially block this
+		single-threaded event loop; since the listener is level-triggered, any backlog left over
+		is simply handled on the next tick.
+	*/
+	fn accept_clients(&mut self) {
+		for _ in 0..MAX_ACCEPTS_PER_TICK {
+			let Ok((stream, addr)) = self.tcp_listener.accept() else {
+				break;
+			};
+			match self.accept_client(stream, addr) {
+				Ok(new_bridge) => {
+					self.bridges.insert(addr, new_bridge);
 				}
 				Err(err) => {
-					if err.kind() == io::ErrorKind::ConnectionReset {
-						return false;
-					}
-					if err.kind() != io::ErrorKind::WouldBlock {
-						dbg!(&err);
-					}
-					true
+					println!("Rejecting client {addr}: {err:?}");
 				}
-			});
+			}
+		}
+	}
 
-		Ok(())
+	/// Performs socket tuning, the TLS handshake (if configured) and `Bridge` setup for a single accepted client.
+	fn accept_client(&self, stream: TcpStream, addr: SocketAddr) -> Res<Bridge> {
+		let tcp_fd = stream.as_raw_fd();
+		configure_client_socket(&stream, &self.config.tcp)?;
+		let tcp: Box<dyn ReliableTransport> = match &self.tls_config {
+			Some(tls_config) => {
+				// The handshake is driven by blocking reads/writes, so the socket has to be
+				// taken out of non-blocking mode for its duration; a deadline bounds how long a
+				// silent client can hold up this handshake on the shared event loop thread.
+				stream.set_nonblocking(false)?;
+				stream.set_read_timeout(Some(TLS_HANDSHAKE_TIMEOUT))?;
+				stream.set_write_timeout(Some(TLS_HANDSHAKE_TIMEOUT))?;
+				let conn = rustls::ServerConnection::new(tls_config.clone())
+					.map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+				let mut tls_stream = rustls::StreamOwned::new(conn, stream);
+				tls_stream.conn.complete_io(&mut tls_stream.sock)?;
+				tls_stream.sock.set_nonblocking(true)?;
+				Box::new(tls_stream)
+			}
+			None => {
+				stream.set_nonblocking(true)?;
+				Box::new(stream)
+			}
+		};
+		let conn = Connection::from(tcp, tcp_fd, self.udp_socket.try_clone()?, addr)?;
+		self.create_bridge(conn)
+	}
+
+	/// Reads any datagrams available on the shared unreliable socket and routes them to the bridge for the sending peer, if one exists.
+	fn udp_receive(&mut self) -> Res<()> {
+		let mut buf = [0; MAX_UDP_DATAGRAM_SIZE];
+		loop {
+			let (length, addr) = match self.udp_socket.recv_from(&mut buf) {
+				Ok(x) => x,
+				Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+				Err(err) => return Err(err),
+			};
+
+			let Some(bridge) = self.bridges.get_mut(&addr) else {
+				continue;
+			};
+			bridge
+				.forward_unreliable_to_server(&buf[..length])
+				.unwrap_or_else(|err| {
+					println!("Error in `udp_receive`: {err:?}");
+				});
+		}
+	}
+
+	/// Sends keepalive pings to bridges that have been quiet for a while, and tears down any that have exceeded the configured idle timeout.
+	fn reap_idle_bridges(&mut self) {
+		let mut reaped = Vec::new();
+		self.bridges.retain(|addr, bridge| {
+			if bridge.is_idle() {
+				bridge.notify_disconnect().unwrap_or_else(|err| {
+					println!("Error in `reap_idle_bridges`: {err:?}");
+				});
+				reaped.push((*addr, bridge.raknet_raw_fd(), bridge.client_raw_fd()));
+				return false;
+			}
+			bridge.maybe_send_keepalive().unwrap_or_else(|err| {
+				println!("Error in `reap_idle_bridges`: {err:?}");
+			});
+			true
+		});
+		for (addr, raknet_fd, client_fd) in reaped {
+			self.deregister_bridge(&addr, raknet_fd, client_fd);
+		}
 	}
 
 	fn create_bridge(&self, source: Connection) -> Res<Bridge> {
@@ -150,6 +403,13 @@ impl Drop for Shim {
 	}
 }
 
+/// Certificate and key to serve on the reliable side of the TcpUdp protocol. If absent from the config, that connection is left in plaintext.
+#[derive(Deserialize, Debug, Clone)]
+pub struct TlsConfig {
+	cert_path: String,
+	key_path: String,
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct AppConfig {
 	external_ip: String,
@@ -157,6 +417,118 @@ pub struct AppConfig {
 	raknet_ip: String,
 	raknet_auth_port: u16,
 	bind_to: String,
+	tls: Option<TlsConfig>,
+	/// How long a bridge may go without any activity before it's reaped. 0 disables idle reaping.
+	#[serde(default)]
+	idle_timeout_secs: u64,
+	/// Artificial latency, jitter and packet loss for testing the shim under adverse network conditions. Disabled unless configured.
+	fault_injection: Option<FaultInjectionConfig>,
+	/// TCP socket tuning for the reliable side of the protocol. Defaulted if absent from the config.
+	#[serde(default)]
+	tcp: TcpConfig,
+}
+
+/// TCP socket tuning, applied to the listener and every accepted client connection, so operators can trade throughput for latency per deployment.
+#[derive(Deserialize, Debug, Clone)]
+pub struct TcpConfig {
+	/// Whether to disable Nagle's algorithm (`TCP_NODELAY`) on accepted client connections. Defaults to on, since the whole point of this protocol is low-latency message delivery.
+	#[serde(default = "default_nodelay")]
+	nodelay: bool,
+	/// Send buffer size for accepted client connections, in bytes. Uses the OS default if unset.
+	send_buffer_size: Option<usize>,
+	/// Receive buffer size for accepted client connections, in bytes. Uses the OS default if unset.
+	recv_buffer_size: Option<usize>,
+	/// Backlog for the `TcpListener`'s accept queue. Uses a backlog of 128 if unset.
+	backlog: Option<u32>,
+}
+
+fn default_nodelay() -> bool {
+	true
+}
+
+impl Default for TcpConfig {
+	fn default() -> Self {
+		TcpConfig {
+			nodelay: default_nodelay(),
+			send_buffer_size: None,
+			recv_buffer_size: None,
+			backlog: None,
+		}
+	}
+}
+
+/// Binds a `TcpListener` at `addr`, applying `backlog` if set (128 otherwise). Goes through `socket2` since `TcpListener::bind` doesn't expose control over the accept queue's backlog.
+fn bind_tcp_listener(addr: SocketAddr, backlog: Option<u32>) -> Res<TcpListener> {
+	let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+	socket.set_reuse_address(true)?;
+	socket.bind(&addr.into())?;
+	socket.listen(backlog.unwrap_or(128) as i32)?;
+	Ok(socket.into())
+}
+
+/**
+	Applies `config`'s `TCP_NODELAY` and buffer-size tuning to an accepted client connection, without taking ownership of it.
+
+	An invalid buffer size (e.g. one rejected by the OS as too large) is logged and otherwise ignored, leaving that connection on the OS default, rather than failing the whole connection over a tuning setting.
+*/
+fn configure_client_socket(stream: &TcpStream, config: &TcpConfig) -> Res<()> {
+	let sock = SockRef::from(stream);
+	sock.set_nodelay(config.nodelay)?;
+	if let Some(size) = config.send_buffer_size {
+		if let Err(err) = sock.set_send_buffer_size(size) {
+			println!("Could not set TCP send buffer size to {size}: {err}");
+		}
+	}
+	if let Some(size) = config.recv_buffer_size {
+		if let Err(err) = sock.set_recv_buffer_size(size) {
+			println!("Could not set TCP receive buffer size to {size}: {err}");
+		}
+	}
+	Ok(())
+}
+
+/// Builds a rustls server config from the certificate and key paths in `tls`, once per `Shim` so connections don't each re-parse them.
+fn load_tls_config(tls: &TlsConfig) -> Res<Arc<rustls::ServerConfig>> {
+	let cert_file = File::open(&tls.cert_path).map_err(|err| {
+		io::Error::new(
+			io::ErrorKind::NotFound,
+			format!("Could not read TLS certificate `{}`: {err}", tls.cert_path),
+		)
+	})?;
+	let certs: Vec<CertificateDer<'static>> = rustls_pemfile::certs(&mut BufReader::new(cert_file))
+		.collect::<Result<_, _>>()
+		.map_err(|err| {
+			io::Error::new(
+				io::ErrorKind::InvalidData,
+				format!("Could not parse TLS certificate `{}`: {err}", tls.cert_path),
+			)
+		})?;
+
+	let key_file = File::open(&tls.key_path).map_err(|err| {
+		io::Error::new(
+			io::ErrorKind::NotFound,
+			format!("Could not read TLS key `{}`: {err}", tls.key_path),
+		)
+	})?;
+	let key: PrivateKeyDer<'static> = rustls_pemfile::private_key(&mut BufReader::new(key_file))
+		.map_err(|err| {
+			io::Error::new(
+				io::ErrorKind::InvalidData,
+				format!("Could not parse TLS key `{}`: {err}", tls.key_path),
+			)
+		})?
+		.ok_or_else(|| {
+			io::Error::new(
+				io::ErrorKind::InvalidData,
+				format!("No private key found in `{}`", tls.key_path),
+			)
+		})?;
+
+	let config = rustls::ServerConfig::builder()
+		.with_no_client_auth()
+		.with_single_cert(certs, key)
+		.map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+	Ok(Arc::new(config))
 }
 
 fn load_config() -> Result<AppConfig, io::Error> {
@@ -196,11 +568,27 @@ fn main() -> Res<()> {
 	addrs.insert(connect_addr, listen_addr);
 	shims.push(Shim::new(listen_addr, connect_addr, config.clone())?);
 
+	let mut poll = Poll::new()?;
+	let mut events = Events::with_capacity(1024);
+	let mut next_token = 0;
+
 	loop {
-		let mut cmds = vec![];
+		for shim in shims.iter_mut() {
+			shim.register_sockets(poll.registry(), &mut next_token)?;
+		}
+
+		let timeout = shims
+			.iter()
+			.filter_map(Shim::next_timer)
+			.min()
+			.unwrap_or(MAX_POLL_TIMEOUT)
+			.min(MAX_POLL_TIMEOUT);
+		poll.poll(&mut events, Some(timeout))?;
 
+		let mut cmds = vec![];
 		for shim in shims.iter_mut() {
-			shim.step(&mut cmds, &addrs)?;
+			let (ready_shim, ready_bridges) = shim.ready_targets(&events);
+			shim.step(&mut cmds, &addrs, ready_shim, &ready_bridges)?;
 		}
 		for cmd in cmds {
 			match cmd {
@@ -210,6 +598,20 @@ fn main() -> Res<()> {
 				}
 			}
 		}
-		thread::sleep(SLEEP_TIME);
 	}
 }
+
+/// Registers `fd` for readability with a freshly allocated token, recording what that token refers to in `token_targets` so a later readiness event can be routed back to it. Returns the token so the caller can remember it for later deregistration.
+fn register_fd(
+	registry: &mio::Registry,
+	fd: RawFd,
+	next_token: &mut usize,
+	token_targets: &mut HashMap<Token, PollTarget>,
+	target: PollTarget,
+) -> Res<Token> {
+	let token = Token(*next_token);
+	*next_token += 1;
+	registry.register(&mut SourceFd(&fd), token, Interest::READABLE)?;
+	token_targets.insert(token, target);
+	Ok(token)
+}
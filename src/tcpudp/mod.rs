@@ -8,13 +8,36 @@
 	Unreliable packets are sent over UDP, prefixed with an 8-bit ID for distinguishing between `Unreliable` (ID 0) and `UnreliableSequenced` (ID 1). In the case of `UnreliableSequenced`, a 32-bit sequence number is prefixed as well. To keep the protocol simple, no support for packet splitting is included, unreliable packets must be shorter than the MTU.
 */
 use std::io::Error;
-use std::io::ErrorKind::WouldBlock;
+use std::io::ErrorKind::InvalidInput;
 use std::io::Result as Res;
+use std::io::{Read, Write};
 
-use endio::LEWrite;
+use endio::{LERead, LEWrite};
 
-use crate::bridge::Packet;
-use std::net::{SocketAddr, TcpStream as ReliableTransport};
+use crate::bridge::{Packet, Reliability};
+use std::net::{SocketAddr, UdpSocket};
+use std::os::fd::RawFd;
+
+/**
+	Any full-duplex byte stream that can back the reliable (TCP-framed) side of the protocol.
+
+	This is implemented for a plain `TcpStream` as well as a TLS stream wrapping one, so `Connection` doesn't need to care whether the underlying transport is encrypted.
+*/
+pub trait ReliableTransport: Read + Write + Send {}
+impl<T: Read + Write + Send> ReliableTransport for T {}
+
+/// The ID byte prefixing an `Unreliable` datagram.
+const ID_UNRELIABLE: u8 = 0;
+/// The ID byte prefixing an `UnreliableSequenced` datagram.
+const ID_UNRELIABLE_SEQUENCED: u8 = 1;
+
+/// Maximum size of an unreliable datagram's payload. Packets larger than this are rejected instead of being split, as the protocol has no splitting mechanism.
+const MTU: usize = 1400;
+
+/// Whether `seq` is newer than `reference` under circular (wraparound) sequence number comparison, rather than a plain numeric `>`, which would break permanently once the sequence counter wraps past `u32::MAX`.
+fn is_seq_newer(seq: u32, reference: u32) -> bool {
+	(seq.wrapping_sub(reference) as i32) > 0
+}
 
 #[derive(Debug)]
 /// Buffer for keeping packets that were only read in part.
@@ -29,33 +52,53 @@ struct BufferOffset {
 	Supports sending and receiving messages in the TCP/UDP protocol.
 
 	By substituting the I and O parameters with types representing the messages you intend to receive (I) and send (O), you can construct an API that only allows sending and receiving of correctly formatted messages, with (de-)serialization done automatically.
-
-	Note: UDP support is not present in this variant as the auth server doesn't need it.
 */
-#[derive(Debug)]
 pub struct Connection {
-	tcp: ReliableTransport,
+	tcp: Box<dyn ReliableTransport>,
+	/// Raw file descriptor of the underlying reliable socket, captured before it was boxed as a trait object, so it can still be registered with a readiness poller.
+	tcp_fd: RawFd,
 	packet: BufferOffset,
+	/// Socket used for sending and receiving unreliable datagrams. Shared with the `Shim` that accepted this connection, since UDP has no concept of an accepted connection to own a socket per peer.
+	udp: UdpSocket,
+	/// Address of the peer on the unreliable side, used as the destination for `send_to`.
+	peer_addr: SocketAddr,
+	/// Sequence number to tag the next outgoing `UnreliableSequenced` datagram with.
+	next_seq: u32,
+	/// Highest sequence number seen so far on an incoming `UnreliableSequenced` datagram. Datagrams whose number is not newer than this, under the wraparound comparison of `is_seq_newer`, are stale and are dropped.
+	highest_seq_received: Option<u32>,
 }
 
 impl Connection {
-	/// Constructs a connection from a previously established TCP or TLS connection.
-	pub fn from(tcp: ReliableTransport) -> Res<Self> {
-		tcp.set_nonblocking(true)?;
+	/**
+		Constructs a connection from a previously established reliable transport (a plain TCP connection, or a TLS stream wrapping one), plus the UDP socket and peer address to use for the unreliable side.
+
+		The transport's non-blocking mode must already be set as desired by the caller; boxing it as a trait object loses access to `set_nonblocking`, and a TLS handshake generally needs to run in blocking mode regardless. `tcp_fd` is the transport's raw file descriptor, captured by the caller before boxing it, so it can still be registered with a readiness poller.
+	*/
+	pub fn from(
+		tcp: Box<dyn ReliableTransport>,
+		tcp_fd: RawFd,
+		udp: UdpSocket,
+		peer_addr: SocketAddr,
+	) -> Res<Self> {
 		Ok(Self {
 			tcp,
+			tcp_fd,
 			packet: BufferOffset {
 				reading_length: true,
 				offset: 0,
 				length: [0; 4],
 				buffer: Box::new([]),
 			},
+			udp,
+			peer_addr,
+			next_seq: 0,
+			highest_seq_received: None,
 		})
 	}
 
-	#[allow(dead_code)]
-	pub fn local_addr(&self) -> Res<SocketAddr> {
-		self.tcp.local_addr()
+	/// The raw file descriptor of the underlying reliable transport, for registering with a readiness poller.
+	pub fn raw_fd(&self) -> RawFd {
+		self.tcp_fd
 	}
 
 	/// Sends bytes over TCP.
@@ -65,24 +108,53 @@ impl Connection {
 		Ok(())
 	}
 
+	/// Sends a single unreliable datagram over UDP, prefixed with its ID byte (and sequence number, for `UnreliableSequenced`).
+	fn send_unreliable(&mut self, data: &[u8], sequenced: bool) -> Res<()> {
+		if data.len() > MTU {
+			return Err(Error::new(
+				InvalidInput,
+				"unreliable payload exceeds MTU and the protocol does not support splitting",
+			));
+		}
+		let mut datagram = Vec::with_capacity(1 + 4 + data.len());
+		if sequenced {
+			datagram.write(ID_UNRELIABLE_SEQUENCED)?;
+			datagram.write(self.next_seq)?;
+			self.next_seq = self.next_seq.wrapping_add(1);
+		} else {
+			datagram.write(ID_UNRELIABLE)?;
+		}
+		std::io::Write::write_all(&mut datagram, data)?;
+		self.udp.send_to(&datagram, self.peer_addr)?;
+		Ok(())
+	}
+
 	pub fn send_packets(&mut self, datas: Vec<Packet>) -> Res<()> {
 		for data in datas {
-			self.send_raw(&data.data)?;
+			match data.reliability {
+				Reliability::Reliable | Reliability::ReliableOrdered => {
+					self.send_raw(&data.data)?;
+				}
+				Reliability::Unreliable => self.send_unreliable(&data.data, false)?,
+				Reliability::UnreliableSequenced => self.send_unreliable(&data.data, true)?,
+			}
 		}
 		Ok(())
 	}
 
-	/// Receives bytes over TCP.
-	pub fn receive_raw(&mut self) -> Res<Box<[u8]>> {
-		use std::io::Read;
+	/**
+		Receives bytes over TCP.
 
+		A genuine zero-length read (as opposed to the underlying `read` call itself returning `WouldBlock`, which propagates through `?` instead) means the peer has closed its end, and is surfaced as `ConnectionAborted` rather than being mistaken for "no data yet".
+	*/
+	pub fn receive_raw(&mut self) -> Res<Box<[u8]>> {
 		if self.packet.reading_length {
 			while self.packet.offset < self.packet.length.len() {
 				let n = self
 					.tcp
 					.read(&mut self.packet.length[self.packet.offset..])?;
 				if n == 0 {
-					return Err(Error::new(WouldBlock, ""));
+					return Err(Error::new(std::io::ErrorKind::ConnectionAborted, ""));
 				}
 				self.packet.offset += n;
 			}
@@ -96,7 +168,7 @@ impl Connection {
 				.tcp
 				.read(&mut self.packet.buffer[self.packet.offset..])?;
 			if n == 0 {
-				return Err(Error::new(WouldBlock, ""));
+				return Err(Error::new(std::io::ErrorKind::ConnectionAborted, ""));
 			}
 			self.packet.offset += n;
 		}
@@ -106,4 +178,31 @@ impl Connection {
 		std::mem::swap(&mut self.packet.buffer, &mut b);
 		Ok(b)
 	}
+
+	/**
+		Parses a raw datagram already received from this connection's peer on the unreliable side.
+
+		Returns `Ok(None)` if the datagram is a stale `UnreliableSequenced` packet that should be silently dropped. The `Shim` is responsible for reading from the shared UDP socket and routing datagrams to the right `Connection` by peer address, so this only handles parsing and sequencing, not the actual `recv_from`.
+	*/
+	pub fn receive_unreliable(&mut self, mut datagram: &[u8]) -> Res<Option<Packet>> {
+		let id: u8 = datagram.read()?;
+		let reliability = match id {
+			ID_UNRELIABLE => Reliability::Unreliable,
+			ID_UNRELIABLE_SEQUENCED => {
+				let seq: u32 = datagram.read()?;
+				if let Some(highest) = self.highest_seq_received {
+					if !is_seq_newer(seq, highest) {
+						return Ok(None);
+					}
+				}
+				self.highest_seq_received = Some(seq);
+				Reliability::UnreliableSequenced
+			}
+			_ => return Err(Error::new(InvalidInput, "unknown unreliable datagram ID")),
+		};
+		Ok(Some(Packet {
+			reliability,
+			data: datagram.to_vec().into_boxed_slice(),
+		}))
+	}
 }